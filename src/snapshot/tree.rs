@@ -1,21 +1,158 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    cell::OnceCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    fs,
+    hash::{Hash, Hasher},
+    io,
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
 };
 
 use rbx_dom_weak::{
     types::{Ref, Variant},
     ustr, Instance, InstanceBuilder, Ustr, UstrMap, WeakDom,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::{multimap::MultiMap, RojoRef};
+use crate::RojoRef;
 
 use super::{InstanceMetadata, InstanceSnapshot};
 
+/// How many removals a [`PendingRemoval`] stays eligible for rename
+/// matching before it's considered stale and evicted. This bounds the map to
+/// a single batch of filesystem events rather than letting it grow unbounded
+/// across a long `serve` session.
+const RENAME_WINDOW: u64 = 64;
+
+/// A short-lived record of an instance that was just removed from the tree,
+/// kept around briefly so that `insert_instance` can recognize a matching
+/// file reappearing under a new path as a rename/move instead of a brand new
+/// instance. This borrows Mercurial's copy-tracing approach: a timestamped
+/// map of removed paths to their old metadata, matched against new
+/// insertions within a small window.
+#[derive(Debug, Clone)]
+struct PendingRemoval {
+    specified_id: Option<RojoRef>,
+    content_hash: u64,
+    basename: Option<String>,
+    class_name: String,
+    revision: u64,
+    parent: Ref,
+}
+
+/// Hashes an instance's class and properties so that an incoming insertion
+/// can be compared against a recently-removed instance without caring about
+/// its `Ref` or path. Property iteration order from `UstrMap` isn't stable,
+/// so entries are sorted by name before hashing.
+fn content_hash(class_name: &str, properties: &UstrMap<Variant>) -> u64 {
+    let mut sorted: Vec<_> = properties.iter().collect();
+    sorted.sort_by_key(|(name, _)| *name);
+
+    let mut hasher = DefaultHasher::new();
+    class_name.hash(&mut hasher);
+    for (name, value) in sorted {
+        name.hash(&mut hasher);
+        format!("{value:?}").hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Checks whether narrowing `metadata` down to just `relevant_paths` and
+/// `specified_id` -- which is all a cached or lazily-materialized instance
+/// keeps around -- would actually lose information, using `Debug` output as
+/// a crude but dependency-free stand-in since `InstanceMetadata` isn't
+/// `PartialEq`. `InstanceMetadata` has other Rojo-specific fields (e.g.
+/// `instigating_source`, `ignore_unknown_instances`) that a warm-restarted
+/// or cache-backed tree would otherwise lose without any indication.
+fn warn_if_metadata_narrows(metadata: &InstanceMetadata) {
+    let narrowed = InstanceMetadata::new()
+        .relevant_paths(metadata.relevant_paths.clone())
+        .specified_id(metadata.specified_id.clone());
+
+    if format!("{narrowed:?}") != format!("{metadata:?}") {
+        log::warn!(
+            "instance metadata has fields beyond relevant_paths/specified_id that won't \
+             survive a tree cache round-trip; this instance's metadata may be incomplete \
+             after a warm restart from cache"
+        );
+    }
+}
+
+/// Holds the metadata for one instance, either fully built or, when restored
+/// from an on-disk [`TreeCache`], deferred until something actually asks for
+/// it. A 50k-instance cached tree shouldn't have to build 50k
+/// `InstanceMetadata` records just to get `RojoTree` back on its feet.
+#[derive(Debug, Clone)]
+enum MetadataEntry {
+    Full(InstanceMetadata),
+    Lazy {
+        relevant_paths: Vec<PathBuf>,
+        specified_id: Option<RojoRef>,
+        materialized: OnceLock<InstanceMetadata>,
+    },
+}
+
+impl MetadataEntry {
+    fn get(&self) -> &InstanceMetadata {
+        match self {
+            MetadataEntry::Full(metadata) => metadata,
+            MetadataEntry::Lazy {
+                relevant_paths,
+                specified_id,
+                materialized,
+            } => materialized.get_or_init(|| {
+                InstanceMetadata::new()
+                    .relevant_paths(relevant_paths.clone())
+                    .specified_id(specified_id.clone())
+            }),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut InstanceMetadata {
+        if let MetadataEntry::Lazy {
+            relevant_paths,
+            specified_id,
+            ..
+        } = self
+        {
+            let metadata = InstanceMetadata::new()
+                .relevant_paths(std::mem::take(relevant_paths))
+                .specified_id(specified_id.take());
+            *self = MetadataEntry::Full(metadata);
+        }
+
+        match self {
+            MetadataEntry::Full(metadata) => metadata,
+            MetadataEntry::Lazy { .. } => unreachable!("just materialized above"),
+        }
+    }
+
+    /// Pulls the path/ID bookkeeping out of this entry without materializing
+    /// a `Lazy` entry into a `Full` one, since `RojoTree::remove` only needs
+    /// those two fields to clean up its auxiliary maps.
+    fn into_parts(self) -> (Vec<PathBuf>, Option<RojoRef>) {
+        match self {
+            MetadataEntry::Full(metadata) => (metadata.relevant_paths, metadata.specified_id),
+            MetadataEntry::Lazy {
+                relevant_paths,
+                specified_id,
+                ..
+            } => (relevant_paths, specified_id),
+        }
+    }
+}
+
 /// An expanded variant of rbx_dom_weak's `WeakDom` that tracks additional
 /// metadata per instance that's Rojo-specific.
 ///
 /// This tree is also optimized for doing fast incremental updates and patches.
+///
+/// The `metadata_map`, `path_to_ids` and `specified_id_to_refs` maps below
+/// are backed by `im`'s persistent collections rather than `std`'s, so that [`RojoTree::checkpoint`] can clone them in
+/// O(1) via structural sharing -- the checkpoint and the live tree share
+/// their underlying nodes until one of them is mutated, at which point only
+/// the changed path is copied.
 #[derive(Debug)]
 pub struct RojoTree {
     /// Contains the instances without their Rojo-specific metadata.
@@ -23,7 +160,7 @@ pub struct RojoTree {
 
     /// Metadata associated with each instance that is kept up-to-date with the
     /// set of actual instances.
-    metadata_map: HashMap<Ref, InstanceMetadata>,
+    metadata_map: im::HashMap<Ref, MetadataEntry>,
 
     /// A multimap from source paths to all of the root instances that were
     /// constructed from that path.
@@ -32,13 +169,34 @@ pub struct RojoTree {
     /// value portion of the map is also a set in order to support the same path
     /// appearing multiple times in the same Rojo project. This is sometimes
     /// called "path aliasing" in various Rojo documentation.
-    path_to_ids: MultiMap<PathBuf, Ref>,
+    path_to_ids: im::OrdMap<PathBuf, im::HashSet<Ref>>,
 
     /// A map of specified RojoRefs to underlying Refs they represent.
-    /// This field is a MultiMap to allow for the possibility of the user specifying
+    /// This field is a multimap to allow for the possibility of the user specifying
     /// the same RojoRef for multiple different instances. An entry containing
     /// multiple elements is an error condition that should be raised to the user.
-    specified_id_to_refs: MultiMap<RojoRef, Ref>,
+    specified_id_to_refs: im::HashMap<RojoRef, im::HashSet<Ref>>,
+
+    /// Recently removed instances, keyed by their primary source path, kept
+    /// around briefly so a matching insertion can be recognized as a
+    /// rename/move rather than a new instance. See [`PendingRemoval`].
+    pending_removals: HashMap<PathBuf, PendingRemoval>,
+
+    /// A logical clock bumped on every removal, used to evict stale entries
+    /// from `pending_removals` once they fall outside of `RENAME_WINDOW`.
+    revision: u64,
+}
+
+/// A structurally-shared snapshot of a [`RojoTree`]'s auxiliary maps, taken
+/// via [`RojoTree::checkpoint`]. The change processor can take one of these
+/// before applying a batch of filesystem events, then [`RojoTree::restore`]
+/// it if property resolution on the resulting tree fails, turning what used
+/// to be a best-effort mutation into something closer to a transaction.
+#[derive(Debug, Clone)]
+pub struct TreeCheckpoint {
+    metadata_map: im::HashMap<Ref, MetadataEntry>,
+    path_to_ids: im::OrdMap<PathBuf, im::HashSet<Ref>>,
+    specified_id_to_refs: im::HashMap<RojoRef, im::HashSet<Ref>>,
 }
 
 impl RojoTree {
@@ -49,9 +207,11 @@ impl RojoTree {
 
         let mut tree = RojoTree {
             inner: WeakDom::new(root_builder),
-            metadata_map: HashMap::new(),
-            path_to_ids: MultiMap::new(),
-            specified_id_to_refs: MultiMap::new(),
+            metadata_map: im::HashMap::new(),
+            path_to_ids: im::OrdMap::new(),
+            specified_id_to_refs: im::HashMap::new(),
+            pending_removals: HashMap::new(),
+            revision: 0,
         };
 
         let root_ref = tree.inner.root_ref();
@@ -74,23 +234,25 @@ impl RojoTree {
     }
 
     pub fn get_instance(&self, id: Ref) -> Option<InstanceWithMeta> {
-        if let Some(instance) = self.inner.get_by_ref(id) {
-            let metadata = self.metadata_map.get(&id).unwrap();
+        let instance = self.inner.get_by_ref(id)?;
+        // `restore` can roll `metadata_map` back past an instance that's
+        // still physically present in `inner` (see its doc comment), so a
+        // missing entry here isn't a bug -- it means this instance's Rojo
+        // identity was rolled back and it should be treated as gone.
+        let metadata = self.metadata_map.get(&id)?.get();
 
-            Some(InstanceWithMeta { instance, metadata })
-        } else {
-            None
-        }
+        Some(InstanceWithMeta { instance, metadata })
     }
 
     pub fn get_instance_mut(&mut self, id: Ref) -> Option<InstanceWithMetaMut> {
-        if let Some(instance) = self.inner.get_by_ref_mut(id) {
-            let metadata = self.metadata_map.get_mut(&id).unwrap();
-
-            Some(InstanceWithMetaMut { instance, metadata })
-        } else {
-            None
+        if self.metadata_map.get(&id).is_none() {
+            return None;
         }
+
+        let instance = self.inner.get_by_ref_mut(id)?;
+        let metadata = self.metadata_map.get_mut(&id).unwrap().get_mut();
+
+        Some(InstanceWithMetaMut { instance, metadata })
     }
 
     pub fn insert_instance(&mut self, parent_ref: Ref, snapshot: InstanceSnapshot) -> Ref {
@@ -124,7 +286,34 @@ impl RojoTree {
             .with_properties(snapshot.properties);
 
         let referent = self.inner.insert(parent_ref, builder);
-        self.insert_metadata(referent, snapshot.metadata);
+
+        // Hash the instance as inserted, including any properties added
+        // above, so it compares like-for-like with `track_pending_removal`.
+        let (hash, class_name) = {
+            let instance = self.inner.get_by_ref(referent).unwrap();
+            (content_hash(&instance.class, &instance.properties), instance.class)
+        };
+        let basename = snapshot
+            .metadata
+            .relevant_paths
+            .first()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned());
+        let rename_source = self.find_pending_rename(hash, &class_name, basename.as_deref());
+
+        let mut metadata = snapshot.metadata;
+        if let Some(source_path) = rename_source {
+            // This is a rename/move: carry the old identity across instead
+            // of letting it get dropped along with the instance we just
+            // destroyed, so `specified_id_to_refs` entries and any
+            // cross-references into this instance keep working.
+            if let Some(pending) = self.pending_removals.remove(&source_path) {
+                if metadata.specified_id.is_none() {
+                    metadata.specified_id = pending.specified_id;
+                }
+            }
+        }
+        self.insert_metadata(referent, metadata);
 
         for child in snapshot.children {
             self.insert_instance(referent, child);
@@ -133,7 +322,54 @@ impl RojoTree {
         referent
     }
 
+    /// Looks for a [`PendingRemoval`] that plausibly corresponds to the
+    /// instance about to be inserted. Prefers an exact content hash match;
+    /// falls back to matching by basename and class so that two files with
+    /// identical content moving at once each still get paired with some
+    /// source instead of neither matching.
+    fn find_pending_rename(
+        &self,
+        hash: u64,
+        class_name: &str,
+        basename: Option<&str>,
+    ) -> Option<PathBuf> {
+        let mut hash_matches = self
+            .pending_removals
+            .iter()
+            .filter(|(_, pending)| pending.content_hash == hash);
+
+        if let Some((path, _)) = hash_matches.next() {
+            // Only trust the content hash if it's the lone match: when two
+            // files with identical content move at once, both removals share
+            // a hash, and picking whichever one a `HashMap` happens to
+            // iterate first can swap their specified ids. Fall through to
+            // basename/class matching instead.
+            if hash_matches.next().is_none() {
+                return Some(path.clone());
+            }
+        }
+
+        let basename = basename?;
+        self.pending_removals
+            .iter()
+            .find(|(_, pending)| {
+                pending.class_name == class_name && pending.basename.as_deref() == Some(basename)
+            })
+            .map(|(path, _)| path.clone())
+    }
+
+    /// Looks up the former parent of whatever was most recently removed from
+    /// `path`, if it's still within `RENAME_WINDOW`. This lets a caller (such
+    /// as `diff_sourcemap`) recover a deleted instance's ancestry even though
+    /// `remove` has already evicted `path` from `path_to_ids` by the time it
+    /// runs.
+    pub fn removed_parent(&self, path: &Path) -> Option<Ref> {
+        self.pending_removals.get(path).map(|pending| pending.parent)
+    }
+
     pub fn remove(&mut self, id: Ref) {
+        self.track_pending_removal(id);
+
         let mut to_move = VecDeque::new();
         to_move.push_back(id);
 
@@ -148,47 +384,74 @@ impl RojoTree {
         self.inner.destroy(id);
     }
 
+    /// Records the instance about to be removed as a [`PendingRemoval`] so a
+    /// matching insertion shortly after can be recognized as a rename/move,
+    /// then advances the logical clock and evicts anything that's fallen
+    /// outside of `RENAME_WINDOW`.
+    fn track_pending_removal(&mut self, id: Ref) {
+        if let (Some(instance), Some(metadata)) = (
+            self.inner.get_by_ref(id),
+            self.metadata_map.get(&id).map(MetadataEntry::get),
+        ) {
+            if let Some(path) = metadata.relevant_paths.first() {
+                let basename = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+
+                self.pending_removals.insert(
+                    path.clone(),
+                    PendingRemoval {
+                        specified_id: metadata.specified_id.clone(),
+                        content_hash: content_hash(&instance.class, &instance.properties),
+                        basename,
+                        class_name: instance.class.to_string(),
+                        revision: self.revision,
+                        parent: instance.parent(),
+                    },
+                );
+            }
+        }
+
+        self.revision += 1;
+        let revision = self.revision;
+        self.pending_removals
+            .retain(|_, pending| revision.saturating_sub(pending.revision) <= RENAME_WINDOW);
+    }
+
     /// Replaces the metadata associated with the given instance ID.
     pub fn update_metadata(&mut self, id: Ref, metadata: InstanceMetadata) {
-        use std::collections::hash_map::Entry;
-
-        match self.metadata_map.entry(id) {
-            Entry::Occupied(mut entry) => {
-                let existing_metadata = entry.get();
-
-                // If this instance's source path changed, we need to update our
-                // path associations so that file changes will trigger updates
-                // to this instance correctly.
-                if existing_metadata.relevant_paths != metadata.relevant_paths {
-                    for existing_path in &existing_metadata.relevant_paths {
-                        self.path_to_ids.remove(existing_path, id);
-                    }
+        let existing_metadata = self.metadata_map.get(&id).map(|entry| entry.get().clone());
+
+        if let Some(existing_metadata) = existing_metadata {
+            // If this instance's source path changed, we need to update our
+            // path associations so that file changes will trigger updates
+            // to this instance correctly.
+            if existing_metadata.relevant_paths != metadata.relevant_paths {
+                for existing_path in &existing_metadata.relevant_paths {
+                    self.path_to_ids_remove(existing_path, id);
+                }
 
-                    for new_path in &metadata.relevant_paths {
-                        self.path_to_ids.insert(new_path.clone(), id);
-                    }
+                for new_path in &metadata.relevant_paths {
+                    self.path_to_ids_insert(new_path.clone(), id);
                 }
-                if existing_metadata.specified_id != metadata.specified_id {
-                    // We need to uphold the invariant that each ID can only map
-                    // to one referent.
-                    if let Some(new) = &metadata.specified_id {
-                        if !self.specified_id_to_refs.get(new).is_empty() {
-                            log::error!("Duplicate user-specified referent '{new}'");
-                        }
-
-                        self.specified_id_to_refs.insert(new.clone(), id);
-                    }
-                    if let Some(old) = &existing_metadata.specified_id {
-                        self.specified_id_to_refs.remove(old, id);
+            }
+            if existing_metadata.specified_id != metadata.specified_id {
+                // We need to uphold the invariant that each ID can only map
+                // to one referent.
+                if let Some(new) = &metadata.specified_id {
+                    if self.has_specified_id(new) {
+                        log::error!("Duplicate user-specified referent '{new}'");
                     }
-                }
 
-                entry.insert(metadata);
-            }
-            Entry::Vacant(entry) => {
-                entry.insert(metadata);
+                    self.specified_id_to_refs_insert(new.clone(), id);
+                }
+                if let Some(old) = &existing_metadata.specified_id {
+                    self.specified_id_to_refs_remove(old, id);
+                }
             }
         }
+
+        self.metadata_map.insert(id, MetadataEntry::Full(metadata));
     }
 
     pub fn descendants(&self, id: Ref) -> RojoDescendants<'_> {
@@ -198,60 +461,413 @@ impl RojoTree {
         RojoDescendants { queue, tree: self }
     }
 
-    pub fn get_ids_at_path(&self, path: &Path) -> &[Ref] {
-        self.path_to_ids.get(path)
+    pub fn get_ids_at_path(&self, path: &Path) -> Vec<Ref> {
+        self.path_to_ids
+            .get(path)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
     }
 
     pub fn get_metadata(&self, id: Ref) -> Option<&InstanceMetadata> {
-        self.metadata_map.get(&id)
+        self.metadata_map.get(&id).map(MetadataEntry::get)
     }
 
     /// Get the backing Ref of the given RojoRef. If the RojoRef maps to exactly
     /// one Ref, this method returns Some. Otherwise, it returns None.
     pub fn get_specified_id(&self, specified: &RojoRef) -> Option<Ref> {
-        match self.specified_id_to_refs.get(specified)[..] {
-            [referent] => Some(referent),
+        match self.specified_id_to_refs.get(specified) {
+            Some(refs) if refs.len() == 1 => refs.iter().next().copied(),
             _ => None,
         }
     }
 
     pub fn set_specified_id(&mut self, id: Ref, specified: RojoRef) {
-        if let Some(metadata) = self.metadata_map.get_mut(&id) {
+        if let Some(entry) = self.metadata_map.get_mut(&id) {
+            let metadata = entry.get_mut();
             if let Some(old) = metadata.specified_id.replace(specified.clone()) {
-                self.specified_id_to_refs.remove(&old, id);
+                self.specified_id_to_refs_remove(&old, id);
             }
         }
-        self.specified_id_to_refs.insert(specified, id);
+        self.specified_id_to_refs_insert(specified, id);
+    }
+
+    fn has_specified_id(&self, specified: &RojoRef) -> bool {
+        self.specified_id_to_refs
+            .get(specified)
+            .map_or(false, |refs| !refs.is_empty())
+    }
+
+    fn path_to_ids_insert(&mut self, path: PathBuf, id: Ref) {
+        self.path_to_ids.entry(path).or_default().insert(id);
+    }
+
+    fn path_to_ids_remove(&mut self, path: &Path, id: Ref) {
+        let mut now_empty = false;
+
+        if let Some(ids) = self.path_to_ids.get_mut(path) {
+            ids.remove(&id);
+            now_empty = ids.is_empty();
+        }
+
+        if now_empty {
+            self.path_to_ids.remove(path);
+        }
+    }
+
+    fn specified_id_to_refs_insert(&mut self, specified: RojoRef, id: Ref) {
+        self.specified_id_to_refs
+            .entry(specified)
+            .or_default()
+            .insert(id);
+    }
+
+    fn specified_id_to_refs_remove(&mut self, specified: &RojoRef, id: Ref) {
+        let mut now_empty = false;
+
+        if let Some(refs) = self.specified_id_to_refs.get_mut(specified) {
+            refs.remove(&id);
+            now_empty = refs.is_empty();
+        }
+
+        if now_empty {
+            self.specified_id_to_refs.remove(specified);
+        }
     }
 
     fn insert_metadata(&mut self, id: Ref, metadata: InstanceMetadata) {
         for path in &metadata.relevant_paths {
-            self.path_to_ids.insert(path.clone(), id);
+            self.path_to_ids_insert(path.clone(), id);
         }
 
         if let Some(specified_id) = &metadata.specified_id {
-            if !self.specified_id_to_refs.get(specified_id).is_empty() {
+            if self.has_specified_id(specified_id) {
                 log::error!("Duplicate user-specified referent '{specified_id}'");
             }
 
             self.set_specified_id(id, specified_id.clone());
         }
 
-        self.metadata_map.insert(id, metadata);
+        self.metadata_map.insert(id, MetadataEntry::Full(metadata));
+    }
+
+    /// Registers an instance's path/ID bookkeeping directly from its
+    /// already-known parts, without ever building a full `InstanceMetadata`
+    /// for it. Used when restoring instances from a [`TreeCache`], where
+    /// `InstanceMetadata` is only built on demand by `get_metadata`.
+    fn insert_lazy_metadata(
+        &mut self,
+        id: Ref,
+        relevant_paths: Vec<PathBuf>,
+        specified_id: Option<RojoRef>,
+    ) {
+        for path in &relevant_paths {
+            self.path_to_ids_insert(path.clone(), id);
+        }
+
+        if let Some(specified_id) = specified_id.clone() {
+            if self.has_specified_id(&specified_id) {
+                log::error!("Duplicate user-specified referent '{specified_id}'");
+            }
+
+            self.specified_id_to_refs_insert(specified_id, id);
+        }
+
+        self.metadata_map.insert(
+            id,
+            MetadataEntry::Lazy {
+                relevant_paths,
+                specified_id,
+                materialized: OnceLock::new(),
+            },
+        );
     }
 
     /// Moves the Rojo metadata from the instance with the given ID from this
-    /// tree into some loose maps.
+    /// tree into some loose maps. A no-op if `id` has no metadata entry --
+    /// this happens when `remove` is called on an instance that a prior
+    /// `restore` already stripped (see `RojoTree::restore`), and callers
+    /// following its advice to clean up via `remove` shouldn't be punished
+    /// for it.
     fn remove_metadata(&mut self, id: Ref) {
-        let metadata = self.metadata_map.remove(&id).unwrap();
+        let Some(entry) = self.metadata_map.remove(&id) else {
+            return;
+        };
+        let (relevant_paths, specified_id) = entry.into_parts();
 
-        if let Some(specified) = metadata.specified_id {
-            self.specified_id_to_refs.remove(&specified, id);
+        if let Some(specified) = specified_id {
+            self.specified_id_to_refs_remove(&specified, id);
         }
 
-        for path in &metadata.relevant_paths {
-            self.path_to_ids.remove(path, id);
+        for path in &relevant_paths {
+            self.path_to_ids_remove(path, id);
+        }
+    }
+
+    /// Takes a structurally-shared snapshot of this tree's auxiliary maps.
+    /// Cheap to call even on a large tree: nothing is deep-copied until a
+    /// mutation on either the checkpoint or the live tree forces a path of
+    /// the underlying persistent map to diverge.
+    pub fn checkpoint(&self) -> TreeCheckpoint {
+        TreeCheckpoint {
+            metadata_map: self.metadata_map.clone(),
+            path_to_ids: self.path_to_ids.clone(),
+            specified_id_to_refs: self.specified_id_to_refs.clone(),
+        }
+    }
+
+    /// Restores this tree's auxiliary maps to a previously-taken
+    /// [`TreeCheckpoint`], discarding any bookkeeping changes made since.
+    /// Note that this only rolls back the metadata layer; the caller is
+    /// responsible for undoing any corresponding mutations to the
+    /// underlying `WeakDom` (an instance left behind can be destroyed with
+    /// `RojoTree::remove`). This is *not* a fully
+    /// atomic rollback: if the caller doesn't also undo its `inner`
+    /// mutations, an instance inserted after the checkpoint remains
+    /// physically present in `inner` with no metadata. Rather than panic on
+    /// that mismatch, `get_instance`/`get_instance_mut`/`get_metadata`/
+    /// `descendants` simply treat such an instance as if it doesn't exist.
+    pub fn restore(&mut self, checkpoint: TreeCheckpoint) {
+        self.metadata_map = checkpoint.metadata_map;
+        self.path_to_ids = checkpoint.path_to_ids;
+        self.specified_id_to_refs = checkpoint.specified_id_to_refs;
+    }
+
+    /// Builds a fresh [`TreeCache`] snapshot of this tree for on-disk
+    /// persistence, keyed by `project_hash` so a cache from a different
+    /// project isn't mistakenly loaded back in.
+    pub fn to_cache(&self, project_hash: u64) -> TreeCache {
+        TreeCache::capture(self, project_hash)
+    }
+
+    /// Rebuilds a `RojoTree` from a previously-captured [`TreeCache`].
+    /// Instance shapes are restored eagerly, but each instance's
+    /// `InstanceMetadata` is deferred until `get_metadata` first asks for it.
+    ///
+    /// Any subtree below the root backed by a path that's changed (or gained
+    /// a new sibling)
+    /// since the cache was captured is skipped rather than trusted, and its
+    /// relevant paths are returned alongside the tree so the caller can
+    /// re-snapshot just those and insert them back in -- a single stale file
+    /// no longer throws away the whole cache.
+    pub fn from_cache(cache: TreeCache) -> (RojoTree, Vec<PathBuf>) {
+        let stale = cache.stale_paths();
+
+        let root_builder = InstanceBuilder::new(cache.root.class_name)
+            .with_name(cache.root.name)
+            .with_properties(cache.root.properties);
+
+        let mut tree = RojoTree {
+            inner: WeakDom::new(root_builder),
+            metadata_map: im::HashMap::new(),
+            path_to_ids: im::OrdMap::new(),
+            specified_id_to_refs: im::HashMap::new(),
+            pending_removals: HashMap::new(),
+            revision: 0,
+        };
+
+        let root_ref = tree.inner.root_ref();
+        tree.insert_lazy_metadata(
+            root_ref,
+            cache.root.relevant_paths,
+            cache.root.specified_id,
+        );
+
+        let mut needs_rebuild = Vec::new();
+        for child in cache.root.children {
+            tree.insert_cached(root_ref, child, &stale, &mut needs_rebuild);
+        }
+
+        (tree, needs_rebuild)
+    }
+
+    /// Whether `node` (or the directory any of its relevant paths lives in)
+    /// shows up in `stale`, meaning it can't be trusted from the cache.
+    fn cached_node_is_stale(node: &CachedInstance, stale: &HashSet<PathBuf>) -> bool {
+        node.relevant_paths.iter().any(|path| {
+            stale.contains(path) || path.parent().is_some_and(|dir| stale.contains(dir))
+        })
+    }
+
+    fn insert_cached(
+        &mut self,
+        parent_ref: Ref,
+        node: CachedInstance,
+        stale: &HashSet<PathBuf>,
+        needs_rebuild: &mut Vec<PathBuf>,
+    ) -> Option<Ref> {
+        if Self::cached_node_is_stale(&node, stale) {
+            needs_rebuild.extend(node.relevant_paths);
+            return None;
+        }
+
+        let builder = InstanceBuilder::empty()
+            .with_class(node.class_name)
+            .with_name(node.name)
+            .with_properties(node.properties);
+
+        let referent = self.inner.insert(parent_ref, builder);
+        self.insert_lazy_metadata(referent, node.relevant_paths, node.specified_id);
+
+        for child in node.children {
+            self.insert_cached(referent, child, stale, needs_rebuild);
+        }
+
+        Some(referent)
+    }
+}
+
+/// One instance's worth of data as stored in a [`TreeCache`]: enough to
+/// rebuild the underlying `WeakDom` node plus, lazily, its
+/// `InstanceMetadata`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CachedInstance {
+    class_name: String,
+    name: String,
+    properties: UstrMap<Variant>,
+    relevant_paths: Vec<PathBuf>,
+    specified_id: Option<RojoRef>,
+    children: Vec<CachedInstance>,
+}
+
+/// A fingerprint of a source file at the time a [`TreeCache`] was captured,
+/// used to tell whether the file has changed since without re-parsing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+struct FileFingerprint {
+    modified_unix_nanos: u128,
+    len: u64,
+}
+
+impl FileFingerprint {
+    fn of(path: &Path) -> io::Result<FileFingerprint> {
+        let meta = fs::metadata(path)?;
+        let modified_unix_nanos = meta
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+
+        Ok(FileFingerprint {
+            modified_unix_nanos,
+            len: meta.len(),
+        })
+    }
+}
+
+/// An on-disk snapshot of a [`RojoTree`], keyed by a hash of the project
+/// that produced it so a cache from a different project (or a different
+/// configuration of the same one) isn't mistakenly reused. This lets
+/// `serve`/`build` skip a full filesystem walk and `InstanceSnapshot`
+/// reconstruction on a warm restart of a large project.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TreeCache {
+    project_hash: u64,
+    root: CachedInstance,
+    fingerprints: HashMap<PathBuf, FileFingerprint>,
+}
+
+impl TreeCache {
+    fn capture(tree: &RojoTree, project_hash: u64) -> TreeCache {
+        let mut fingerprints = HashMap::new();
+        let root = Self::capture_node(tree, tree.get_root_id(), &mut fingerprints);
+
+        TreeCache {
+            project_hash,
+            root,
+            fingerprints,
+        }
+    }
+
+    fn capture_node(
+        tree: &RojoTree,
+        id: Ref,
+        fingerprints: &mut HashMap<PathBuf, FileFingerprint>,
+    ) -> CachedInstance {
+        let instance = tree.get_instance(id).expect("instance did not exist");
+
+        warn_if_metadata_narrows(instance.metadata());
+
+        for path in &instance.metadata().relevant_paths {
+            if let Ok(fingerprint) = FileFingerprint::of(path) {
+                fingerprints.insert(path.clone(), fingerprint);
+            }
+
+            // A file's own fingerprint can't tell us about a sibling that
+            // didn't exist yet when the cache was captured, so fingerprint
+            // its containing directory too: most filesystems bump a
+            // directory's modified time when an entry is added or removed,
+            // which is enough to flag the directory (and everything we
+            // cached under it) as stale.
+            if let Some(dir) = path.parent() {
+                if let Ok(fingerprint) = FileFingerprint::of(dir) {
+                    fingerprints.entry(dir.to_path_buf()).or_insert(fingerprint);
+                }
+            }
+        }
+
+        CachedInstance {
+            class_name: instance.class_name().to_string(),
+            name: instance.name().to_string(),
+            properties: instance.properties().clone(),
+            relevant_paths: instance.metadata().relevant_paths.clone(),
+            specified_id: instance.metadata().specified_id.clone(),
+            children: instance
+                .children()
+                .iter()
+                .map(|&child| Self::capture_node(tree, child, fingerprints))
+                .collect(),
+        }
+    }
+
+    /// Writes this cache to `path` as JSON.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = fs::File::create(path)?;
+        serde_json::to_writer(file, self)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+    }
+
+    /// Loads a cache from `path`, returning `None` if it doesn't exist, can't
+    /// be parsed, or was captured for a different project -- in which case
+    /// the caller should fall back to a full filesystem walk. File
+    /// fingerprints are not checked here; `RojoTree::from_cache` skips the
+    /// stale subtrees.
+    pub fn load(path: &Path, project_hash: u64) -> io::Result<Option<TreeCache>> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let cache: TreeCache = match serde_json::from_reader(file) {
+            Ok(cache) => cache,
+            Err(_) => return Ok(None),
+        };
+
+        if cache.project_hash != project_hash {
+            return Ok(None);
         }
+
+        Ok(Some(cache))
+    }
+
+    /// Every cached path (file or directory) whose fingerprint no longer
+    /// matches what's on disk. Staleness is resolved per-path rather than
+    /// for the cache as a whole, so that `RojoTree::from_cache` only has to
+    /// rebuild the specific subtrees affected instead of discarding
+    /// everything over a single changed file.
+    fn stale_paths(&self) -> HashSet<PathBuf> {
+        self.fingerprints
+            .iter()
+            .filter(|(path, expected)| {
+                FileFingerprint::of(path)
+                    .map(|actual| actual != **expected)
+                    .unwrap_or(true)
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
     }
 }
 
@@ -264,22 +880,26 @@ impl<'a> Iterator for RojoDescendants<'a> {
     type Item = InstanceWithMeta<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let id = self.queue.pop_front()?;
-
-        let instance = self
-            .tree
-            .inner
-            .get_by_ref(id)
-            .expect("Instance did not exist");
-
-        let metadata = self
-            .tree
-            .get_metadata(instance.referent())
-            .expect("Metadata did not exist for instance");
-
-        self.queue.extend(instance.children().iter().copied());
+        while let Some(id) = self.queue.pop_front() {
+            let Some(instance) = self.tree.inner.get_by_ref(id) else {
+                continue;
+            };
+
+            // See `RojoTree::restore`'s doc comment: an instance can still
+            // be physically present in `inner` with no metadata if a
+            // checkpoint was restored past it. Treat it (and skip
+            // descending into it) as if it doesn't exist rather than
+            // panicking.
+            let Some(metadata) = self.tree.get_metadata(instance.referent()) else {
+                continue;
+            };
+
+            self.queue.extend(instance.children().iter().copied());
+
+            return Some(InstanceWithMeta { instance, metadata });
+        }
 
-        Some(InstanceWithMeta { instance, metadata })
+        None
     }
 }
 
@@ -375,6 +995,10 @@ impl InstanceWithMetaMut<'_> {
 
 #[cfg(test)]
 mod test {
+    use std::{fs, path::PathBuf};
+
+    use rbx_dom_weak::{ustr, types::Variant, UstrMap};
+
     use crate::{
         snapshot::{InstanceMetadata, InstanceSnapshot},
         RojoRef,
@@ -398,4 +1022,268 @@ mod test {
         tree.remove(original);
         assert_eq!(tree.get_specified_id(&custom_ref.clone()), Some(duped));
     }
+
+    #[test]
+    fn insert_instance_recognizes_a_rename_via_matching_content_hash() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let root_id = tree.get_root_id();
+
+        let custom_ref = RojoRef::new("Keepsake".into());
+        let mut properties = UstrMap::default();
+        properties.insert(ustr("Value"), Variant::Bool(true));
+
+        let original = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(properties.clone())
+            .metadata(
+                InstanceMetadata::new()
+                    .relevant_paths(vec![PathBuf::from("old.txt")])
+                    .specified_id(Some(custom_ref.clone())),
+            );
+
+        let original_id = tree.insert_instance(root_id, original);
+        tree.remove(original_id);
+
+        // Same class and properties, but a brand new path -- this should be
+        // recognized as a rename rather than a new instance, carrying the
+        // old specified_id across.
+        let moved = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(properties)
+            .metadata(InstanceMetadata::new().relevant_paths(vec![PathBuf::from("new.txt")]));
+
+        let moved_id = tree.insert_instance(root_id, moved);
+
+        assert_eq!(tree.get_specified_id(&custom_ref), Some(moved_id));
+    }
+
+    #[test]
+    fn insert_instance_falls_back_to_basename_and_class_when_content_differs() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let root_id = tree.get_root_id();
+
+        let custom_ref = RojoRef::new("Keepsake".into());
+
+        let mut old_properties = UstrMap::default();
+        old_properties.insert(ustr("Value"), Variant::Bool(true));
+
+        let original = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(old_properties)
+            .metadata(
+                InstanceMetadata::new()
+                    .relevant_paths(vec![PathBuf::from("dir/thing.txt")])
+                    .specified_id(Some(custom_ref.clone())),
+            );
+
+        let original_id = tree.insert_instance(root_id, original);
+        tree.remove(original_id);
+
+        // The file's content changed (so the content hash won't match), but
+        // it's the same basename under a new directory -- still recognized
+        // as a move.
+        let mut new_properties = UstrMap::default();
+        new_properties.insert(ustr("Value"), Variant::Bool(false));
+
+        let moved = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(new_properties)
+            .metadata(
+                InstanceMetadata::new().relevant_paths(vec![PathBuf::from("elsewhere/thing.txt")]),
+            );
+
+        let moved_id = tree.insert_instance(root_id, moved);
+
+        assert_eq!(tree.get_specified_id(&custom_ref), Some(moved_id));
+    }
+
+    #[test]
+    fn find_pending_rename_falls_back_to_basename_when_content_hash_is_ambiguous() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let root_id = tree.get_root_id();
+
+        let ref_a = RojoRef::new("KeepsakeA".into());
+        let ref_b = RojoRef::new("KeepsakeB".into());
+
+        let mut properties = UstrMap::default();
+        properties.insert(ustr("Value"), Variant::Bool(true));
+
+        let a = InstanceSnapshot::new()
+            .name("A")
+            .class_name("BoolValue")
+            .properties(properties.clone())
+            .metadata(
+                InstanceMetadata::new()
+                    .relevant_paths(vec![PathBuf::from("dir_a/thing_a.txt")])
+                    .specified_id(Some(ref_a.clone())),
+            );
+        let b = InstanceSnapshot::new()
+            .name("B")
+            .class_name("BoolValue")
+            .properties(properties.clone())
+            .metadata(
+                InstanceMetadata::new()
+                    .relevant_paths(vec![PathBuf::from("dir_b/thing_b.txt")])
+                    .specified_id(Some(ref_b.clone())),
+            );
+
+        let a_id = tree.insert_instance(root_id, a);
+        let b_id = tree.insert_instance(root_id, b);
+
+        // Both move at once with identical (unchanged) content, so the
+        // content hash alone can't tell the two pending removals apart --
+        // only the basename can.
+        tree.remove(a_id);
+        tree.remove(b_id);
+
+        let moved_a = InstanceSnapshot::new()
+            .name("A")
+            .class_name("BoolValue")
+            .properties(properties.clone())
+            .metadata(
+                InstanceMetadata::new().relevant_paths(vec![PathBuf::from("new_dir/thing_a.txt")]),
+            );
+        let moved_b = InstanceSnapshot::new()
+            .name("B")
+            .class_name("BoolValue")
+            .properties(properties)
+            .metadata(
+                InstanceMetadata::new().relevant_paths(vec![PathBuf::from("new_dir/thing_b.txt")]),
+            );
+
+        let moved_a_id = tree.insert_instance(root_id, moved_a);
+        let moved_b_id = tree.insert_instance(root_id, moved_b);
+
+        assert_eq!(tree.get_specified_id(&ref_a), Some(moved_a_id));
+        assert_eq!(tree.get_specified_id(&ref_b), Some(moved_b_id));
+    }
+
+    #[test]
+    fn pending_rename_is_evicted_after_the_rename_window_elapses() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let root_id = tree.get_root_id();
+
+        let custom_ref = RojoRef::new("Keepsake".into());
+        let mut properties = UstrMap::default();
+        properties.insert(ustr("Value"), Variant::Bool(true));
+
+        let original = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(properties.clone())
+            .metadata(
+                InstanceMetadata::new()
+                    .relevant_paths(vec![PathBuf::from("old.txt")])
+                    .specified_id(Some(custom_ref.clone())),
+            );
+
+        let original_id = tree.insert_instance(root_id, original);
+        tree.remove(original_id);
+
+        // Advance the logical clock well past RENAME_WINDOW with unrelated
+        // removals so the pending rename record gets evicted.
+        for i in 0..100 {
+            let filler = tree.insert_instance(root_id, InstanceSnapshot::new().name(format!("Filler{i}")));
+            tree.remove(filler);
+        }
+
+        let moved = InstanceSnapshot::new()
+            .name("Thing")
+            .class_name("BoolValue")
+            .properties(properties)
+            .metadata(InstanceMetadata::new().relevant_paths(vec![PathBuf::from("new.txt")]));
+
+        tree.insert_instance(root_id, moved);
+
+        // The rename window elapsed, so this is treated as a brand new
+        // instance rather than inheriting the old specified_id.
+        assert_eq!(tree.get_specified_id(&custom_ref), None);
+    }
+
+    #[test]
+    fn from_cache_skips_only_the_subtree_with_a_new_sibling_file() {
+        let dir = std::env::temp_dir().join(format!(
+            "rojo_tree_cache_test_{}_new_sibling",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let fresh_path = dir.join("fresh.txt");
+        fs::write(&fresh_path, b"fresh").unwrap();
+
+        let other_dir = dir.join("untouched");
+        fs::create_dir_all(&other_dir).unwrap();
+        let other_path = other_dir.join("other.txt");
+        fs::write(&other_path, b"other").unwrap();
+
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let root_id = tree.get_root_id();
+
+        tree.insert_instance(
+            root_id,
+            InstanceSnapshot::new()
+                .name("Fresh")
+                .metadata(InstanceMetadata::new().relevant_paths(vec![fresh_path.clone()])),
+        );
+        tree.insert_instance(
+            root_id,
+            InstanceSnapshot::new()
+                .name("Other")
+                .metadata(InstanceMetadata::new().relevant_paths(vec![other_path.clone()])),
+        );
+
+        let cache = tree.to_cache(1234);
+
+        // A brand new sibling shows up in `fresh_path`'s directory after the
+        // cache was captured; nothing about `fresh_path` itself changed.
+        fs::write(dir.join("new_sibling.txt"), b"new").unwrap();
+
+        let (restored, needs_rebuild) = RojoTree::from_cache(cache);
+
+        assert!(needs_rebuild.contains(&fresh_path));
+        assert!(!needs_rebuild.contains(&other_path));
+
+        let remaining_names: Vec<_> = restored
+            .descendants(restored.get_root_id())
+            .map(|instance| instance.name().to_string())
+            .collect();
+        assert!(!remaining_names.contains(&"Fresh".to_string()));
+        assert!(remaining_names.contains(&"Other".to_string()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_does_not_panic_on_instances_inserted_after_the_checkpoint() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let checkpoint = tree.checkpoint();
+
+        let id = tree.insert_instance(tree.get_root_id(), InstanceSnapshot::new());
+        tree.restore(checkpoint);
+
+        // `id` is still present in the underlying WeakDom -- `restore` only
+        // rolls back the metadata layer -- but its metadata was rolled back
+        // away. That should degrade to "doesn't exist" rather than panic.
+        assert!(tree.get_instance(id).is_none());
+        assert!(tree.get_metadata(id).is_none());
+    }
+
+    #[test]
+    fn remove_does_not_panic_on_an_instance_already_stripped_by_restore() {
+        let mut tree = RojoTree::new(InstanceSnapshot::new());
+        let checkpoint = tree.checkpoint();
+
+        let id = tree.insert_instance(tree.get_root_id(), InstanceSnapshot::new());
+        tree.restore(checkpoint);
+
+        // `restore`'s doc comment tells callers to clean up any instances
+        // left physically present in the WeakDom via `remove`. That's the
+        // only public mutation path available, so it must not panic on an
+        // id whose metadata `restore` already rolled back.
+        tree.remove(id);
+    }
 }
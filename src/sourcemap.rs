@@ -1,12 +1,19 @@
+use std::collections::{HashMap, HashSet};
+
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use rbx_dom_weak::types::Ref;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
-use crate::snapshot::RojoTree;
+use crate::snapshot::{InstanceWithMeta, RojoTree};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct SourcemapNode {
+    /// An identity for this node, used to correlate nodes across the patches
+    /// emitted by `diff_sourcemap`. This is the instance's user-specified ID
+    /// if it has one, which survives tree rebuilds, and its `Ref` otherwise,
+    /// which only holds for as long as the instance lives in the same tree.
+    pub id: String,
     pub name: String,
     pub class_name: String,
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -15,6 +22,40 @@ pub struct SourcemapNode {
     pub children: Vec<SourcemapNode>,
 }
 
+/// A patch between two sourcemaps, keyed by [`SourcemapNode::id`], produced
+/// by [`diff_sourcemap`] for `rojo sourcemap --watch` so that connected
+/// editor tooling can update its in-memory map instead of re-scanning the
+/// whole tree on every change.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SourcemapPatch {
+    pub added: Vec<SourcemapNode>,
+    pub updated: Vec<SourcemapNode>,
+    pub removed: Vec<String>,
+}
+
+fn node_id(instance: &InstanceWithMeta) -> String {
+    match &instance.metadata().specified_id {
+        Some(specified) => format!("id:{specified}"),
+        None => format!("ref:{:?}", instance.id()),
+    }
+}
+
+/// Sorts a node's children by a key that doesn't depend on thread scheduling
+/// or directory-read order, so that two sourcemaps built from the same
+/// project state are bit-identical and checked-in sourcemaps/CI diffs don't
+/// pick up noisy reorderings. The final tiebreaker is the node's first
+/// relevant file path rather than its `id`: unspecified ids fall back to a
+/// `Ref`'s internal representation, which is assigned in allocation order
+/// and isn't stable across rebuilds.
+fn sort_children(children: &mut [SourcemapNode]) {
+    children.sort_by(|a, b| {
+        a.class_name
+            .cmp(&b.class_name)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.file_paths.first().cmp(&b.file_paths.first()))
+    });
+}
+
 pub(crate) fn recurse_create_node<'a>(
     tree: &'a RojoTree,
     referent: Ref,
@@ -22,7 +63,7 @@ pub(crate) fn recurse_create_node<'a>(
 ) -> Option<SourcemapNode> {
     let instance = tree.get_instance(referent).expect("instance did not exist");
 
-    let children: Vec<_> = instance
+    let mut children: Vec<_> = instance
         .children()
         .par_iter()
         .filter_map(|&child_id| recurse_create_node(tree, child_id, project_dir))
@@ -34,7 +75,19 @@ pub(crate) fn recurse_create_node<'a>(
         return None;
     }
 
-    let file_paths = instance
+    sort_children(&mut children);
+
+    Some(SourcemapNode {
+        id: node_id(&instance),
+        name: instance.name().into(),
+        class_name: instance.class_name().into(),
+        file_paths: relevant_file_paths(&instance, project_dir),
+        children,
+    })
+}
+
+fn relevant_file_paths(instance: &InstanceWithMeta, project_dir: &Path) -> Vec<PathBuf> {
+    instance
         .metadata()
         .relevant_paths
         .iter()
@@ -42,12 +95,372 @@ pub(crate) fn recurse_create_node<'a>(
         .filter(|path| path.is_file())
         .filter_map(|path| path.strip_prefix(project_dir).ok())
         .map(|path| path.to_path_buf())
+        .collect()
+}
+
+/// Like `recurse_create_node`, but walks sequentially and records the ID of
+/// every instance that actually survives into the final tree (i.e. every
+/// instance for which this function returns `Some`) into `visited`, so the
+/// caller can tell which previously-known nodes fell out of this subtree.
+///
+/// Instances that get filtered out (because they have no children) must
+/// *not* be marked visited: a node that drops out of the tree on this pass
+/// still needs to show up in the removed set, and it can only do that if
+/// `diff_sourcemap` doesn't see it as "touched".
+fn recurse_create_node_tracked(
+    tree: &RojoTree,
+    referent: Ref,
+    project_dir: &Path,
+    visited: &mut HashSet<String>,
+) -> Option<SourcemapNode> {
+    let instance = tree.get_instance(referent).expect("instance did not exist");
+
+    let mut children: Vec<_> = instance
+        .children()
+        .iter()
+        .filter_map(|&child_id| recurse_create_node_tracked(tree, child_id, project_dir, visited))
         .collect();
 
+    if children.is_empty() {
+        return None;
+    }
+
+    sort_children(&mut children);
+
+    let id = node_id(&instance);
+    visited.insert(id.clone());
+
     Some(SourcemapNode {
+        id,
         name: instance.name().into(),
         class_name: instance.class_name().into(),
-        file_paths,
+        file_paths: relevant_file_paths(&instance, project_dir),
         children,
     })
 }
+
+fn flatten_ids(node: &SourcemapNode, out: &mut HashSet<String>) {
+    out.insert(node.id.clone());
+    for child in &node.children {
+        flatten_ids(child, out);
+    }
+}
+
+/// Walks a freshly recomputed subtree against `previous` and records every
+/// node whose value actually changed (or is brand new). If `node` compares
+/// equal to the previously-known node with the same id, its whole subtree is
+/// necessarily unchanged too (value equality is structural), so there's no
+/// need to recurse any further.
+fn collect_changes(
+    node: &SourcemapNode,
+    previous: &HashMap<String, SourcemapNode>,
+    patch: &mut SourcemapPatch,
+) {
+    match previous.get(&node.id) {
+        Some(old) if old == node => return,
+        Some(_) => patch.updated.push(node.clone()),
+        None => patch.added.push(node.clone()),
+    }
+
+    for child in &node.children {
+        collect_changes(child, previous, patch);
+    }
+}
+
+/// Marks `start` and each of its ancestors dirty, stopping as soon as it
+/// reaches an instance that's already marked (everything above it is already
+/// covered by that earlier walk) or one that no longer exists.
+fn walk_up_marking_dirty(tree: &RojoTree, start: Ref, dirty: &mut HashSet<Ref>) {
+    let mut current = Some(start);
+
+    while let Some(id) = current {
+        if !dirty.insert(id) {
+            break;
+        }
+
+        let instance = match tree.get_instance(id) {
+            Some(instance) => instance,
+            None => break,
+        };
+
+        let parent = instance.parent();
+        current = if parent.is_none() { None } else { Some(parent) };
+    }
+}
+
+/// Computes the minimal patch needed to bring a previously-emitted sourcemap
+/// up to date after `changed_paths` were touched, instead of rebuilding the
+/// whole tree from the root.
+///
+/// This reuses `RojoTree::get_ids_at_path` to find the instances backed by
+/// each changed path, then walks up their ancestors (since an ancestor's
+/// `children` list may now be stale). A changed path with no live instances
+/// left at it (an ordinary deletion) falls back to `RojoTree::removed_parent`
+/// to recover the ancestor chain to walk, since `remove` has already evicted
+/// the path from `path_to_ids` by the time this runs. An ancestor chain stops
+/// being walked the moment it reaches an instance that's already known to be
+/// dirty, since everything above that instance is already covered by its own
+/// walk. Of the resulting dirty set, only the *topmost* dirty instance in
+/// each chain is actually recomputed: recomputing a node's subtree also
+/// recomputes every dirty descendant below it in the same pass, so
+/// recomputing those descendants separately would just redo that work.
+/// `previous` should be a flattened index of the last sourcemap this was
+/// diffed against, keyed by `SourcemapNode::id`.
+pub fn diff_sourcemap(
+    tree: &RojoTree,
+    previous: &HashMap<String, SourcemapNode>,
+    changed_paths: &[PathBuf],
+    project_dir: &Path,
+) -> SourcemapPatch {
+    let mut dirty = HashSet::new();
+
+    for path in changed_paths {
+        let live_ids = tree.get_ids_at_path(path);
+
+        if !live_ids.is_empty() {
+            for id in live_ids {
+                walk_up_marking_dirty(tree, id, &mut dirty);
+            }
+            continue;
+        }
+
+        // `RojoTree::remove` evicts an instance's own path from
+        // `path_to_ids` before this can run, so a plain deletion --
+        // `changed_paths` containing only the deleted file's own path, with
+        // nothing else touched -- resolves to zero live ids here even though
+        // it's exactly the case this function needs to catch. Recover the
+        // deleted instance's former parent, which `remove` keeps around
+        // briefly for rename detection, and walk up from there instead.
+        if let Some(parent) = tree.removed_parent(path) {
+            walk_up_marking_dirty(tree, parent, &mut dirty);
+        }
+    }
+
+    let dirty_roots = dirty.iter().copied().filter(|&id| match tree.get_instance(id) {
+        Some(instance) => !dirty.contains(&instance.parent()),
+        None => true,
+    });
+
+    let mut patch = SourcemapPatch::default();
+    let mut touched = HashSet::new();
+
+    for root in dirty_roots {
+        let Some(root_instance) = tree.get_instance(root) else {
+            continue;
+        };
+        let root_id = node_id(&root_instance);
+
+        let node = recurse_create_node_tracked(tree, root, project_dir, &mut touched);
+
+        if let Some(node) = &node {
+            collect_changes(node, previous, &mut patch);
+        }
+
+        if let Some(old_root) = previous.get(&root_id) {
+            let mut old_ids = HashSet::new();
+            flatten_ids(old_root, &mut old_ids);
+            patch
+                .removed
+                .extend(old_ids.difference(&touched).cloned());
+        }
+    }
+
+    patch
+}
+
+/// Flattens a sourcemap tree into an index keyed by `SourcemapNode::id`, for
+/// use as the `previous` argument to a later `diff_sourcemap` call.
+pub fn flatten_sourcemap(root: &SourcemapNode) -> HashMap<String, SourcemapNode> {
+    let mut index = HashMap::new();
+    let mut queue = vec![root];
+
+    while let Some(node) = queue.pop() {
+        queue.extend(node.children.iter());
+        index.insert(node.id.clone(), node.clone());
+    }
+
+    index
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snapshot::{InstanceMetadata, InstanceSnapshot, RojoTree};
+
+    use super::*;
+
+    fn snapshot(name: &str, class_name: &str, path: Option<&str>) -> InstanceSnapshot {
+        let mut metadata = InstanceMetadata::new();
+        if let Some(path) = path {
+            metadata = metadata.relevant_paths(vec![PathBuf::from(path)]);
+        }
+
+        InstanceSnapshot::new()
+            .name(name)
+            .class_name(class_name)
+            .metadata(metadata)
+    }
+
+    #[test]
+    fn tracked_recursion_only_marks_nodes_touched_when_they_survive_the_filter() {
+        let tree = RojoTree::new(snapshot("Game", "DataModel", None));
+        let root_id = tree.get_root_id();
+
+        let mut touched = HashSet::new();
+        let node = recurse_create_node_tracked(&tree, root_id, Path::new(""), &mut touched);
+
+        assert!(node.is_none());
+        assert!(!touched.contains(&node_id(&tree.get_instance(root_id).unwrap())));
+    }
+
+    #[test]
+    fn diff_reports_vanished_ancestor_as_removed_not_silently_dropped() {
+        let mut tree = RojoTree::new(snapshot("Game", "DataModel", None));
+        let root_id = tree.get_root_id();
+        let mid_id = tree.insert_instance(root_id, snapshot("Mid", "Folder", Some("mid")));
+
+        let mid_node_id = node_id(&tree.get_instance(mid_id).unwrap());
+        let root_node_id = node_id(&tree.get_instance(root_id).unwrap());
+
+        // Hand-construct the sourcemap a prior build had emitted, back when
+        // `Mid` still had a child of its own.
+        let previous_mid = SourcemapNode {
+            id: mid_node_id.clone(),
+            name: "Mid".into(),
+            class_name: "Folder".into(),
+            file_paths: Vec::new(),
+            children: Vec::new(),
+        };
+        let previous_root = SourcemapNode {
+            id: root_node_id,
+            name: "Game".into(),
+            class_name: "DataModel".into(),
+            file_paths: Vec::new(),
+            children: vec![previous_mid],
+        };
+        let previous = flatten_sourcemap(&previous_root);
+
+        let patch = diff_sourcemap(&tree, &previous, &[PathBuf::from("mid")], Path::new(""));
+
+        assert!(patch.removed.contains(&mid_node_id));
+    }
+
+    #[test]
+    fn diff_only_recomputes_from_the_topmost_dirty_ancestor() {
+        let mut tree = RojoTree::new(snapshot("Game", "DataModel", None));
+        let root_id = tree.get_root_id();
+        let mid_id = tree.insert_instance(root_id, snapshot("Mid", "Folder", Some("mid")));
+        let low_id = tree.insert_instance(mid_id, snapshot("Low", "Folder", Some("low")));
+
+        let root_node_id = node_id(&tree.get_instance(root_id).unwrap());
+        let mid_node_id = node_id(&tree.get_instance(mid_id).unwrap());
+        let low_node_id = node_id(&tree.get_instance(low_id).unwrap());
+
+        let previous_low = SourcemapNode {
+            id: low_node_id.clone(),
+            name: "Low".into(),
+            class_name: "Folder".into(),
+            file_paths: Vec::new(),
+            children: Vec::new(),
+        };
+        let previous_mid = SourcemapNode {
+            id: mid_node_id.clone(),
+            name: "Mid".into(),
+            class_name: "Folder".into(),
+            file_paths: Vec::new(),
+            children: vec![previous_low],
+        };
+        let previous_root = SourcemapNode {
+            id: root_node_id,
+            name: "Game".into(),
+            class_name: "DataModel".into(),
+            file_paths: Vec::new(),
+            children: vec![previous_mid],
+        };
+        let previous = flatten_sourcemap(&previous_root);
+
+        tree.remove(low_id);
+
+        let patch = diff_sourcemap(&tree, &previous, &[PathBuf::from("mid")], Path::new(""));
+
+        // Both `Mid` and `Low` vanish once `Low` is removed. If the ancestor
+        // walk still recomputed every level separately (instead of only the
+        // topmost dirty ancestor), each of these would show up twice: once
+        // from a pass rooted at `Mid` and once from a pass rooted at `Game`.
+        assert_eq!(
+            patch.removed.iter().filter(|id| **id == mid_node_id).count(),
+            1
+        );
+        assert_eq!(
+            patch.removed.iter().filter(|id| **id == low_node_id).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn diff_using_the_deleted_files_own_path_still_finds_its_surviving_ancestor() {
+        let mut tree = RojoTree::new(snapshot("Game", "DataModel", None));
+        let root_id = tree.get_root_id();
+        let mid_id = tree.insert_instance(root_id, snapshot("Mid", "Folder", Some("mid")));
+        let low_id = tree.insert_instance(mid_id, snapshot("Low", "Folder", Some("low")));
+
+        let root_node_id = node_id(&tree.get_instance(root_id).unwrap());
+        let mid_node_id = node_id(&tree.get_instance(mid_id).unwrap());
+        let low_node_id = node_id(&tree.get_instance(low_id).unwrap());
+
+        let previous_low = SourcemapNode {
+            id: low_node_id.clone(),
+            name: "Low".into(),
+            class_name: "Folder".into(),
+            file_paths: Vec::new(),
+            children: Vec::new(),
+        };
+        let previous_mid = SourcemapNode {
+            id: mid_node_id.clone(),
+            name: "Mid".into(),
+            class_name: "Folder".into(),
+            file_paths: Vec::new(),
+            children: vec![previous_low],
+        };
+        let previous_root = SourcemapNode {
+            id: root_node_id,
+            name: "Game".into(),
+            class_name: "DataModel".into(),
+            file_paths: Vec::new(),
+            children: vec![previous_mid],
+        };
+        let previous = flatten_sourcemap(&previous_root);
+
+        tree.remove(low_id);
+
+        // Diff using the deleted file's *own* path, as a real watch loop
+        // would report it -- not an ancestor's, which is the case the other
+        // tests above exercise. `get_ids_at_path("low")` resolves to nothing
+        // once `remove` has run, so this only works if the fallback to
+        // `RojoTree::removed_parent` kicks in.
+        let patch = diff_sourcemap(&tree, &previous, &[PathBuf::from("low")], Path::new(""));
+
+        assert!(patch.removed.contains(&low_node_id));
+        assert!(patch.removed.contains(&mid_node_id));
+    }
+
+    #[test]
+    fn sort_children_breaks_name_ties_on_file_path_not_id() {
+        let node = |id: &str, path: &str| SourcemapNode {
+            id: id.into(),
+            name: "Script".into(),
+            class_name: "Script".into(),
+            file_paths: vec![PathBuf::from(path)],
+            children: Vec::new(),
+        };
+
+        // Deliberately give the alphabetically-later file path the
+        // alphabetically-earlier id, so a correct file-path tiebreak and an
+        // id tiebreak disagree on the resulting order.
+        let mut children = vec![node("ref:z", "b.lua"), node("ref:a", "a.lua")];
+
+        sort_children(&mut children);
+
+        assert_eq!(children[0].file_paths[0], PathBuf::from("a.lua"));
+        assert_eq!(children[1].file_paths[0], PathBuf::from("b.lua"));
+    }
+}